@@ -28,12 +28,142 @@ enum Atom<DatumType, ErrorType> {
 
 use self::Atom::*;
 
-type PreprocessingTokenSource = dyn Source<PreprocessingToken, String>;
-type PreprocessingAtom = Atom<PreprocessingToken, String>;
+type PreprocessingTokenSource = dyn Source<SpannedToken, Diagnostic>;
+type PreprocessingAtom = Atom<SpannedToken, Diagnostic>;
 
 type Identifier = String;
 
-#[derive(Clone, PartialEq, Eq)]
+/// Identifies the file a token was read from.  Tokens synthesised from a macro
+/// replacement list, rather than read from a real file, are attributed to the
+/// `MACRO` pseudo-file.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+struct FileId(usize);
+
+impl FileId {
+    const MACRO: FileId = FileId(usize::MAX);
+}
+
+/// Where a token came from.  Following the span/hygiene model proc_macro
+/// attaches to every `TokenTree`, a token either points at a byte range in a
+/// real source file or records that it was produced by macro expansion.
+#[derive(Clone, PartialEq, Eq, Debug)]
+enum Span {
+    /// A half-open byte range within `file`.
+    Source {
+        start: usize,
+        end: usize,
+        file: FileId,
+    },
+    /// A token produced by expansion: `definition` is its position in the
+    /// replacement list and `call_site` is the span of the invocation that
+    /// produced it.  Nesting `call_site` yields an expansion backtrace.
+    Expansion {
+        definition: Box<Span>,
+        call_site: Box<Span>,
+    },
+}
+
+impl Span {
+    /// The span of a token sitting at `index` in a macro replacement list.  In
+    /// the `MACRO` pseudo-file the range counts tokens, not bytes.
+    fn definition(index: usize) -> Span {
+        Span::Source {
+            start: index,
+            end: index + 1,
+            file: FileId::MACRO,
+        }
+    }
+
+    /// Wrap `self` as the definition site of a token produced by the invocation
+    /// whose span is `call_site`.
+    fn expanded_from(self, call_site: &Span) -> Span {
+        Span::Expansion {
+            definition: Box::new(self),
+            call_site: Box::new(call_site.clone()),
+        }
+    }
+
+    /// The span covering two adjacent tokens, used for `##` results.  Ranges in
+    /// the same file are merged; otherwise the left operand's span is kept.
+    fn join(&self, other: &Span) -> Span {
+        match (self, other) {
+            (
+                Span::Source {
+                    start,
+                    file,
+                    ..
+                },
+                Span::Source {
+                    end,
+                    file: other_file,
+                    ..
+                },
+            ) if file == other_file => Span::Source {
+                start: *start,
+                end: *end,
+                file: *file,
+            },
+            _ => self.clone(),
+        }
+    }
+}
+
+/// A value paired with the per-token metadata the stream carries: the source
+/// span it originated from and the `Spacing` to the following token.
+#[derive(Clone, PartialEq, Eq, Debug)]
+struct Spanned<T> {
+    node: T,
+    span: Span,
+    spacing: Spacing,
+}
+
+impl<T> Spanned<T> {
+    /// Create a token with the given span and `Alone` spacing.
+    fn new(node: T, span: Span) -> Spanned<T> {
+        Spanned {
+            node,
+            span,
+            spacing: Spacing::Alone,
+        }
+    }
+
+    /// Create a token with an explicit trailing spacing.
+    fn spaced(node: T, span: Span, spacing: Spacing) -> Spanned<T> {
+        Spanned {
+            node,
+            span,
+            spacing,
+        }
+    }
+}
+
+type SpannedToken = Spanned<PreprocessingToken>;
+
+/// A preprocessor error, optionally pointing at the span that provoked it so
+/// consumers can render `file:line:col` diagnostics.
+#[derive(Clone, PartialEq, Eq, Debug)]
+struct Diagnostic {
+    message: String,
+    span: Option<Span>,
+}
+
+impl Diagnostic {
+    fn new(message: String) -> Diagnostic {
+        Diagnostic {
+            message,
+            span: None,
+        }
+    }
+
+    fn at(message: String, span: Span) -> Diagnostic {
+        Diagnostic {
+            message,
+            span: Some(span),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
 enum PreprocessingToken {
     HeaderName(HeaderKind, String),
     Identifier(Identifier),
@@ -54,14 +184,16 @@ enum PreprocessingToken {
 /// doesn't care about C semantics, but it help keep the names short and understandable to
 /// those already familiar with C.  The order of the definitions matches the C17 draft
 /// spec (N2176)
-#[derive(Clone, PartialEq, Eq)]
+#[derive(Clone, PartialEq, Eq, Debug)]
 enum Punctuator {
     /* [    */ ArrayIndexBegin,
     /* ]    */ ArrayIndexEnd,
     /* Whether a left paren is preceded by whitespace in a #define differentiates between
-     * a function-like macro and an object macro whose replacement starts with a left paren */
+     * a function-like macro and an object macro whose replacement starts with a left paren.
+     * That whitespace is now recorded by the general per-token `Spacing`, so the paren itself
+     * carries no payload. */
     /* (    */
-    LeftParen(Separation),
+    LeftParen,
     /* )    */ RightParen,
     /* {    */ BlockBegin,
     /* }    */ BlockEnd,
@@ -115,14 +247,19 @@ enum Punctuator {
     /* %:%: */ PreprocessingConcatDigraph,
 }
 
-/// Separation indicates how token is separated
-#[derive(Clone, PartialEq, Eq)]
-enum Separation {
-    Whitespace,
-    None,
+/// Spacing records how a token is separated from the one that follows it, so
+/// that output "closely resembles the input".  Borrowing the Joint/Alone model
+/// syn and proc_macro attach to every punct: `Joint` means the next token
+/// abuts this one with no intervening whitespace, `Alone` means a single space,
+/// and `Whitespace` carries the exact original inter-token text.
+#[derive(Clone, PartialEq, Eq, Debug)]
+enum Spacing {
+    Joint,
+    Alone,
+    Whitespace(String),
 }
 
-#[derive(Clone, PartialEq, Eq)]
+#[derive(Clone, PartialEq, Eq, Debug)]
 enum HeaderKind {
     SystemPath,
     UserPath,
@@ -155,68 +292,788 @@ impl Macros {
 }
 
 enum ExpandingToken {
-    Token(PreprocessingToken),
+    Token(SpannedToken),
     EndScope(Identifier),
 }
 
 struct MacroExpandingTokenSource<'stream> {
     macros: Macros,
     token_stream: &'stream mut PreprocessingTokenSource,
+    /// Tokens that have been spliced back ahead of the inner stream and still
+    /// need to be rescanned for further macros.
+    pushback: VecDeque<ExpandingToken>,
+    /// Macros whose replacement lists are currently being rescanned.
+    active: ReplacedMacros,
 }
 
 impl<'stream> MacroExpandingTokenSource<'stream> {
     fn new(
         macros: Macros,
         token_stream: &'stream mut PreprocessingTokenSource,
-    ) -> MacroExpandingTokenSource {
+    ) -> MacroExpandingTokenSource<'stream> {
         MacroExpandingTokenSource {
             macros,
             token_stream,
+            pushback: VecDeque::new(),
+            active: ReplacedMacros::new(),
         }
     }
 }
 
-impl<'stream> Source<PreprocessingToken, String> for MacroExpandingTokenSource<'stream> {
+impl<'stream> Source<SpannedToken, Diagnostic> for MacroExpandingTokenSource<'stream> {
     fn next(&mut self) -> PreprocessingAtom {
-        match self.token_stream.next() {
-            Datum(token) => Datum(token),
-            Error(error) => Error(error),
-            Empty => Empty,
+        expand(
+            &self.macros,
+            &mut self.pushback,
+            &mut self.active,
+            self.token_stream,
+        )
+    }
+}
+
+/// A bare source over an in-memory token list, used to fully expand macro
+/// arguments through the same machinery as the top-level stream.
+struct TokenListSource {
+    tokens: VecDeque<SpannedToken>,
+}
+
+impl TokenListSource {
+    fn new(tokens: Vec<SpannedToken>) -> TokenListSource {
+        TokenListSource {
+            tokens: tokens.into_iter().collect(),
+        }
+    }
+}
+
+impl Source<SpannedToken, Diagnostic> for TokenListSource {
+    fn next(&mut self) -> PreprocessingAtom {
+        match self.tokens.pop_front() {
+            Some(token) => Datum(token),
+            None => Empty,
+        }
+    }
+}
+
+/// Pull the next element to be rescanned, drawing from the pushback buffer
+/// before falling back to the upstream source.
+fn pull_next(
+    pushback: &mut VecDeque<ExpandingToken>,
+    source: &mut PreprocessingTokenSource,
+) -> Atom<ExpandingToken, Diagnostic> {
+    if let Some(token) = pushback.pop_front() {
+        return Datum(token);
+    }
+    match source.next() {
+        Datum(token) => Datum(ExpandingToken::Token(token)),
+        Error(error) => Error(error),
+        Empty => Empty,
+    }
+}
+
+/// Splice `tokens` in front of the remaining input, preserving their order.
+fn splice_front(pushback: &mut VecDeque<ExpandingToken>, tokens: Vec<ExpandingToken>) {
+    for token in tokens.into_iter().rev() {
+        pushback.push_front(token);
+    }
+}
+
+/// Core expansion loop shared by the top-level source and argument rescanning.
+fn expand(
+    macros: &Macros,
+    pushback: &mut VecDeque<ExpandingToken>,
+    active: &mut ReplacedMacros,
+    source: &mut PreprocessingTokenSource,
+) -> PreprocessingAtom {
+    loop {
+        let next = match pull_next(pushback, source) {
+            Datum(next) => next,
+            Error(error) => return Error(error),
+            Empty => return Empty,
+        };
+        let token = match next {
+            ExpandingToken::EndScope(name) => {
+                active.remove(&name);
+                continue;
+            }
+            ExpandingToken::Token(token) => token,
+        };
+        let name = match &token.node {
+            PreprocessingToken::Identifier(name) => name.clone(),
+            _ => return Datum(token),
+        };
+        // "Blue paint": a macro name met while its own replacement list is still
+        // being rescanned is emitted verbatim and never re-expanded.  The name
+        // leaves the active set when its matching `EndScope` sentinel is pulled.
+        if active.contains(&name) {
+            return Datum(token);
+        }
+        match macros.definitions.get(&name) {
+            Some(Macro::Object(replacement)) => {
+                let mut splice: Vec<ExpandingToken> = replacement
+                    .iter()
+                    .enumerate()
+                    .map(|(index, replacement_token)| {
+                        ExpandingToken::Token(Spanned::new(
+                            replacement_token.clone(),
+                            Span::definition(index).expanded_from(&token.span),
+                        ))
+                    })
+                    .collect();
+                splice.push(ExpandingToken::EndScope(name.clone()));
+                active.insert(name);
+                splice_front(pushback, splice);
+            }
+            Some(Macro::Function(params, replacement)) => {
+                if !peek_left_paren(pushback, active, source) {
+                    return Datum(token);
+                }
+                let arguments = match collect_arguments(params.len(), pushback, active, source) {
+                    Ok(arguments) => arguments,
+                    Err(error) => return Error(error),
+                };
+                if arguments.len() != params.len() {
+                    return Error(Diagnostic::at(
+                        format!(
+                            "macro '{}' passed {} arguments but takes {}",
+                            name,
+                            arguments.len(),
+                            params.len()
+                        ),
+                        token.span.clone(),
+                    ));
+                }
+                let expanded: Vec<Vec<SpannedToken>> = arguments
+                    .iter()
+                    .map(|argument| expand_token_list(macros, argument.clone(), active))
+                    .collect();
+                let substituted =
+                    match substitute(params, replacement, &arguments, &expanded, &token.span) {
+                        Ok(substituted) => substituted,
+                        Err(error) => return Error(error),
+                    };
+                let mut splice: Vec<ExpandingToken> =
+                    substituted.into_iter().map(ExpandingToken::Token).collect();
+                splice.push(ExpandingToken::EndScope(name.clone()));
+                active.insert(name);
+                splice_front(pushback, splice);
+            }
+            None => return Datum(token),
         }
     }
 }
 
+/// Look ahead for the `(` that turns a function-like macro name into an
+/// invocation.  Returns `true` and consumes the paren when present; otherwise
+/// the looked-at token is pushed back untouched.
+fn peek_left_paren(
+    pushback: &mut VecDeque<ExpandingToken>,
+    active: &mut ReplacedMacros,
+    source: &mut PreprocessingTokenSource,
+) -> bool {
+    loop {
+        match pull_next(pushback, source) {
+            Datum(ExpandingToken::EndScope(name)) => {
+                active.remove(&name);
+            }
+            Datum(ExpandingToken::Token(Spanned {
+                node: PreprocessingToken::Punctuator(Punctuator::LeftParen),
+                ..
+            })) => return true,
+            Datum(other) => {
+                pushback.push_front(other);
+                return false;
+            }
+            Error(_) | Empty => return false,
+        }
+    }
+}
+
+/// Collect the comma-separated argument token lists of a function-like macro
+/// invocation, up to the `)` that matches the already-consumed `(`.  Commas and
+/// parens nested inside inner parens do not separate arguments.
+fn collect_arguments(
+    parameter_count: usize,
+    pushback: &mut VecDeque<ExpandingToken>,
+    active: &mut ReplacedMacros,
+    source: &mut PreprocessingTokenSource,
+) -> Result<Vec<Vec<SpannedToken>>, Diagnostic> {
+    let mut arguments: Vec<Vec<SpannedToken>> = Vec::new();
+    let mut current: Vec<SpannedToken> = Vec::new();
+    // Openers seen inside the argument list, paired with their span so an
+    // unclosed delimiter can be reported against its location.  The `(` that
+    // began the invocation is not on the stack; the list ends at the matching
+    // top-level `)`.
+    let mut delimiters: Vec<(Delimiter, Span)> = Vec::new();
+    loop {
+        let token = match pull_next(pushback, source) {
+            Datum(ExpandingToken::EndScope(name)) => {
+                active.remove(&name);
+                continue;
+            }
+            Datum(ExpandingToken::Token(token)) => token,
+            Error(error) => return Err(error),
+            Empty => {
+                return Err(match delimiters.last() {
+                    Some((delimiter, span)) => Diagnostic::at(
+                        format!(
+                            "unclosed '{}' in macro argument list",
+                            delimiter.opening_spelling()
+                        ),
+                        span.clone(),
+                    ),
+                    None => Diagnostic::new(String::from(
+                        "unterminated macro argument list: missing ')'",
+                    )),
+                });
+            }
+        };
+        // Classify the token before acting so the borrow of `token.node` is
+        // released and `token` can be moved into the current argument.
+        let (opened, closed, is_separator) = match &token.node {
+            PreprocessingToken::Punctuator(punctuator) => (
+                Delimiter::opening(punctuator),
+                Delimiter::closing(punctuator),
+                *punctuator == Punctuator::ParameterSeparator,
+            ),
+            _ => (None, None, false),
+        };
+        if let Some(delimiter) = opened {
+            delimiters.push((delimiter, token.span.clone()));
+            current.push(token);
+            continue;
+        }
+        if let Some(closing) = closed {
+            match delimiters.last() {
+                None => {
+                    if closing == Delimiter::Paren {
+                        arguments.push(current);
+                        break;
+                    }
+                    return Err(Diagnostic::at(
+                        format!(
+                            "unexpected closing '{}' in macro argument list",
+                            closing.closing_spelling()
+                        ),
+                        token.span.clone(),
+                    ));
+                }
+                Some((open, open_span)) => {
+                    if *open == closing {
+                        delimiters.pop();
+                        current.push(token);
+                    } else {
+                        let message = format!(
+                            "mismatched '{}': expected '{}' to close '{}'",
+                            closing.closing_spelling(),
+                            open.closing_spelling(),
+                            open.opening_spelling()
+                        );
+                        return Err(Diagnostic::at(message, open_span.clone()));
+                    }
+                }
+            }
+            continue;
+        }
+        if is_separator && delimiters.is_empty() {
+            arguments.push(current);
+            current = Vec::new();
+            continue;
+        }
+        current.push(token);
+    }
+    // `f()` invoking a macro that takes no parameters yields a single empty
+    // argument; treat it as the no-argument case.
+    if parameter_count == 0 && arguments.len() == 1 && arguments[0].is_empty() {
+        arguments.clear();
+    }
+    Ok(arguments)
+}
+
+/// The three balanced delimiter kinds tracked while collecting macro arguments.
+/// Brackets and braces are also recognised in their digraph spellings.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Delimiter {
+    Paren,
+    Bracket,
+    Brace,
+}
+
+impl Delimiter {
+    /// The delimiter opened by `punctuator`, if any (including digraph forms).
+    fn opening(punctuator: &Punctuator) -> Option<Delimiter> {
+        match punctuator {
+            Punctuator::LeftParen => Some(Delimiter::Paren),
+            Punctuator::ArrayIndexBegin | Punctuator::ArrayIndexBeginDigraph => {
+                Some(Delimiter::Bracket)
+            }
+            Punctuator::BlockBegin | Punctuator::BlockBeginDigraph => Some(Delimiter::Brace),
+            _ => None,
+        }
+    }
+
+    /// The delimiter closed by `punctuator`, if any (including digraph forms).
+    fn closing(punctuator: &Punctuator) -> Option<Delimiter> {
+        match punctuator {
+            Punctuator::RightParen => Some(Delimiter::Paren),
+            Punctuator::ArrayIndexEnd | Punctuator::ArrayIndexEndDigraph => {
+                Some(Delimiter::Bracket)
+            }
+            Punctuator::BlockEnd | Punctuator::BlockEndDigraph => Some(Delimiter::Brace),
+            _ => None,
+        }
+    }
+
+    fn opening_spelling(self) -> &'static str {
+        match self {
+            Delimiter::Paren => "(",
+            Delimiter::Bracket => "[",
+            Delimiter::Brace => "{",
+        }
+    }
+
+    fn closing_spelling(self) -> &'static str {
+        match self {
+            Delimiter::Paren => ")",
+            Delimiter::Bracket => "]",
+            Delimiter::Brace => "}",
+        }
+    }
+}
+
+/// A fragment of a replacement list after parameters and `#` have been
+/// resolved, but before `##` pasting has joined neighbouring fragments.
+enum Piece {
+    Tokens(Vec<SpannedToken>),
+    Paste,
+}
+
+/// Substitute arguments for the matching parameter positions in a function-like
+/// macro's replacement list, applying the `#` and `##` operators.
+///
+/// `raw` holds the unexpanded argument tokens; `expanded` holds the
+/// fully-expanded ones.  Operands of `#` and `##` use the raw tokens; every
+/// other parameter is replaced by its expanded argument.  `##` pastes are
+/// applied once the whole list has been substituted.
+fn substitute(
+    params: &Parameters,
+    replacement: &Tokens,
+    raw: &[Vec<SpannedToken>],
+    expanded: &[Vec<SpannedToken>],
+    call_site: &Span,
+) -> Result<Vec<SpannedToken>, Diagnostic> {
+    let mut pieces: Vec<Piece> = Vec::new();
+    let mut index = 0;
+    while index < replacement.len() {
+        let token = &replacement[index];
+        let span = Span::definition(index).expanded_from(call_site);
+        if is_stringize(token) {
+            if let Some(next) = replacement.get(index + 1) {
+                if let Some(parameter) = parameter_index(params, next) {
+                    pieces.push(Piece::Tokens(vec![stringize(&raw[parameter], span)]));
+                    index += 2;
+                    continue;
+                }
+            }
+            pieces.push(Piece::Tokens(vec![Spanned::new(token.clone(), span)]));
+            index += 1;
+            continue;
+        }
+        if is_concat(token) {
+            pieces.push(Piece::Paste);
+            index += 1;
+            continue;
+        }
+        if let Some(parameter) = parameter_index(params, token) {
+            let after_paste = matches!(pieces.last(), Some(Piece::Paste));
+            let before_paste = replacement
+                .get(index + 1)
+                .map(is_concat)
+                .unwrap_or(false);
+            let source = if after_paste || before_paste { raw } else { expanded };
+            pieces.push(Piece::Tokens(source[parameter].clone()));
+            index += 1;
+            continue;
+        }
+        pieces.push(Piece::Tokens(vec![Spanned::new(token.clone(), span)]));
+        index += 1;
+    }
+    fold_pastes(pieces)
+}
+
+/// Collapse the `Piece` sequence, pasting the last token of the left fragment to
+/// the first token of the right fragment at every `Paste` marker.
+fn fold_pastes(pieces: Vec<Piece>) -> Result<Vec<SpannedToken>, Diagnostic> {
+    let mut output: Vec<SpannedToken> = Vec::new();
+    let mut pending_paste = false;
+    for piece in pieces {
+        match piece {
+            Piece::Paste => pending_paste = true,
+            Piece::Tokens(tokens) => {
+                if pending_paste {
+                    pending_paste = false;
+                    let left = output.pop();
+                    let mut tokens = tokens.into_iter();
+                    match (left, tokens.next()) {
+                        (Some(left), Some(right)) => output.push(paste(&left, &right)?),
+                        (Some(left), None) => output.push(left),
+                        (None, Some(right)) => output.push(right),
+                        (None, None) => {}
+                    }
+                    output.extend(tokens);
+                } else {
+                    output.extend(tokens);
+                }
+            }
+        }
+    }
+    Ok(output)
+}
+
+fn parameter_index(params: &Parameters, token: &PreprocessingToken) -> Option<usize> {
+    match token {
+        PreprocessingToken::Identifier(name) => params.iter().position(|param| param == name),
+        _ => None,
+    }
+}
+
+fn is_stringize(token: &PreprocessingToken) -> bool {
+    matches!(
+        token,
+        PreprocessingToken::Punctuator(Punctuator::PreprocessingDirective)
+            | PreprocessingToken::Punctuator(Punctuator::PreprocessingDirectiveDigraph)
+    )
+}
+
+fn is_concat(token: &PreprocessingToken) -> bool {
+    matches!(
+        token,
+        PreprocessingToken::Punctuator(Punctuator::PreprocessingConcat)
+            | PreprocessingToken::Punctuator(Punctuator::PreprocessingConcatDigraph)
+    )
+}
+
+/// Build a single `StringLiteral` from an argument's unexpanded tokens, joining
+/// them with their recorded inter-token `Spacing` and escaping embedded `"` and
+/// `\`.
+fn stringize(tokens: &[SpannedToken], span: Span) -> SpannedToken {
+    let mut joined = String::new();
+    for (position, token) in tokens.iter().enumerate() {
+        if position > 0 {
+            match &tokens[position - 1].spacing {
+                Spacing::Whitespace(whitespace) => joined.push_str(whitespace),
+                Spacing::Alone => joined.push(' '),
+                Spacing::Joint => {}
+            }
+        }
+        joined.push_str(&spelling(&token.node));
+    }
+    let mut escaped = String::new();
+    for character in joined.chars() {
+        if character == '"' || character == '\\' {
+            escaped.push('\\');
+        }
+        escaped.push(character);
+    }
+    Spanned::new(PreprocessingToken::StringLiteral(escaped), span)
+}
+
+/// Paste two tokens into one by concatenating their spellings and re-lexing the
+/// result, erroring if it is not a single valid preprocessing token.  The
+/// pasted token's span is the operands' spans combined by `Span::join`.
+fn paste(left: &SpannedToken, right: &SpannedToken) -> Result<SpannedToken, Diagnostic> {
+    let spelling = format!("{}{}", spelling(&left.node), spelling(&right.node));
+    let span = left.span.join(&right.span);
+    match relex(&spelling) {
+        Some(node) => Ok(Spanned::new(node, span)),
+        None => Err(Diagnostic::at(
+            format!(
+                "pasting \"{}\" and \"{}\" does not form a valid preprocessing token",
+                self::spelling(&left.node),
+                self::spelling(&right.node)
+            ),
+            span,
+        )),
+    }
+}
+
+/// Re-lex a spelling produced by `##` into a single preprocessing token.
+fn relex(text: &str) -> Option<PreprocessingToken> {
+    if text.is_empty() {
+        return None;
+    }
+    if let Some(punctuator) = punctuator_from_spelling(text) {
+        return Some(PreprocessingToken::Punctuator(punctuator));
+    }
+    let first = text.chars().next()?;
+    if first == '_' || first.is_ascii_alphabetic() {
+        if text
+            .chars()
+            .all(|character| character == '_' || character.is_ascii_alphanumeric())
+        {
+            return Some(PreprocessingToken::Identifier(text.to_string()));
+        }
+        return None;
+    }
+    if (first.is_ascii_digit() || first == '.')
+        && text.chars().any(|character| character.is_ascii_digit())
+        && text.chars().all(|character| {
+            character.is_ascii_alphanumeric()
+                || character == '.'
+                || character == '+'
+                || character == '-'
+                || character == '_'
+        })
+    {
+        return Some(PreprocessingToken::PreprocessingNumber(text.to_string()));
+    }
+    None
+}
+
+/// The source spelling of a token, used by `#`, `##`, and source rendering.
+fn spelling(token: &PreprocessingToken) -> String {
+    match token {
+        PreprocessingToken::HeaderName(HeaderKind::SystemPath, name) => format!("<{}>", name),
+        PreprocessingToken::HeaderName(HeaderKind::UserPath, name) => format!("\"{}\"", name),
+        PreprocessingToken::Identifier(name) => name.clone(),
+        PreprocessingToken::PreprocessingNumber(number) => number.clone(),
+        PreprocessingToken::CharacterConstant(character) => format!("'{}'", character),
+        PreprocessingToken::StringLiteral(literal) => format!("\"{}\"", literal),
+        PreprocessingToken::Punctuator(punctuator) => punctuator_spelling(punctuator).to_string(),
+        PreprocessingToken::OtherCharacter(character) => character.to_string(),
+        PreprocessingToken::Newline => String::from("\n"),
+    }
+}
+
+/// The canonical source spelling of a punctuator.
+fn punctuator_spelling(punctuator: &Punctuator) -> &'static str {
+    match punctuator {
+        Punctuator::ArrayIndexBegin => "[",
+        Punctuator::ArrayIndexEnd => "]",
+        Punctuator::LeftParen => "(",
+        Punctuator::RightParen => ")",
+        Punctuator::BlockBegin => "{",
+        Punctuator::BlockEnd => "}",
+        Punctuator::Member => ".",
+        Punctuator::DerefMember => "->",
+        Punctuator::Increment => "++",
+        Punctuator::Decrement => "--",
+        Punctuator::AddressOf => "&",
+        Punctuator::Deference => "*",
+        Punctuator::Add => "+",
+        Punctuator::Substract => "-",
+        Punctuator::BitwiseNot => "~",
+        Punctuator::LogicalNot => "!",
+        Punctuator::Divide => "/",
+        Punctuator::Modulus => "%",
+        Punctuator::ShiftLeft => "<<",
+        Punctuator::ShiftRight => ">>",
+        Punctuator::LessThan => "<",
+        Punctuator::GreaterThan => ">",
+        Punctuator::LessThanOrEquals => "<=",
+        Punctuator::GreaterThanOrEquals => ">=",
+        Punctuator::Equals => "==",
+        Punctuator::NotEquals => "!=",
+        Punctuator::BitwiseXor => "^",
+        Punctuator::BitwiseOr => "|",
+        Punctuator::LogicalAnd => "&&",
+        Punctuator::LogicalOr => "||",
+        Punctuator::TernaryCondition => "?",
+        Punctuator::TernarySeparator => ":",
+        Punctuator::StatementEnd => ";",
+        Punctuator::VariadicParameters => "...",
+        Punctuator::Assignment => "=",
+        Punctuator::MultiplyAndAssign => "*=",
+        Punctuator::DivideAndAssign => "/=",
+        Punctuator::ModulusAndAssign => "%=",
+        Punctuator::AddAndAssign => "+=",
+        Punctuator::SubstractAndAssign => "-=",
+        Punctuator::ShiftLeftAndAssign => "<<=",
+        Punctuator::ShiftRightAndAssign => ">>=",
+        Punctuator::BitwiseAndAndAssign => "&=",
+        Punctuator::BitwiseXorAndAssign => "^=",
+        Punctuator::BitwiseOrAndAssign => "|=",
+        Punctuator::ParameterSeparator => ",",
+        Punctuator::PreprocessingDirective => "#",
+        Punctuator::PreprocessingConcat => "##",
+        Punctuator::ArrayIndexBeginDigraph => "<:",
+        Punctuator::ArrayIndexEndDigraph => ":>",
+        Punctuator::BlockBeginDigraph => "<%",
+        Punctuator::BlockEndDigraph => "%>",
+        Punctuator::PreprocessingDirectiveDigraph => "%:",
+        Punctuator::PreprocessingConcatDigraph => "%:%:",
+    }
+}
+
+/// Recognise a punctuator from its source spelling.
+fn punctuator_from_spelling(text: &str) -> Option<Punctuator> {
+    let punctuator = match text {
+        "[" => Punctuator::ArrayIndexBegin,
+        "]" => Punctuator::ArrayIndexEnd,
+        "(" => Punctuator::LeftParen,
+        ")" => Punctuator::RightParen,
+        "{" => Punctuator::BlockBegin,
+        "}" => Punctuator::BlockEnd,
+        "." => Punctuator::Member,
+        "->" => Punctuator::DerefMember,
+        "++" => Punctuator::Increment,
+        "--" => Punctuator::Decrement,
+        "&" => Punctuator::AddressOf,
+        "*" => Punctuator::Deference,
+        "+" => Punctuator::Add,
+        "-" => Punctuator::Substract,
+        "~" => Punctuator::BitwiseNot,
+        "!" => Punctuator::LogicalNot,
+        "/" => Punctuator::Divide,
+        "%" => Punctuator::Modulus,
+        "<<" => Punctuator::ShiftLeft,
+        ">>" => Punctuator::ShiftRight,
+        "<" => Punctuator::LessThan,
+        ">" => Punctuator::GreaterThan,
+        "<=" => Punctuator::LessThanOrEquals,
+        ">=" => Punctuator::GreaterThanOrEquals,
+        "==" => Punctuator::Equals,
+        "!=" => Punctuator::NotEquals,
+        "^" => Punctuator::BitwiseXor,
+        "|" => Punctuator::BitwiseOr,
+        "&&" => Punctuator::LogicalAnd,
+        "||" => Punctuator::LogicalOr,
+        "?" => Punctuator::TernaryCondition,
+        ":" => Punctuator::TernarySeparator,
+        ";" => Punctuator::StatementEnd,
+        "..." => Punctuator::VariadicParameters,
+        "=" => Punctuator::Assignment,
+        "*=" => Punctuator::MultiplyAndAssign,
+        "/=" => Punctuator::DivideAndAssign,
+        "%=" => Punctuator::ModulusAndAssign,
+        "+=" => Punctuator::AddAndAssign,
+        "-=" => Punctuator::SubstractAndAssign,
+        "<<=" => Punctuator::ShiftLeftAndAssign,
+        ">>=" => Punctuator::ShiftRightAndAssign,
+        "&=" => Punctuator::BitwiseAndAndAssign,
+        "^=" => Punctuator::BitwiseXorAndAssign,
+        "|=" => Punctuator::BitwiseOrAndAssign,
+        "," => Punctuator::ParameterSeparator,
+        "#" => Punctuator::PreprocessingDirective,
+        "##" => Punctuator::PreprocessingConcat,
+        "<:" => Punctuator::ArrayIndexBeginDigraph,
+        ":>" => Punctuator::ArrayIndexEndDigraph,
+        "<%" => Punctuator::BlockBeginDigraph,
+        "%>" => Punctuator::BlockEndDigraph,
+        "%:" => Punctuator::PreprocessingDirectiveDigraph,
+        "%:%:" => Punctuator::PreprocessingConcatDigraph,
+        _ => return None,
+    };
+    Some(punctuator)
+}
+
+/// Fully expand a standalone token list, used to pre-expand macro arguments
+/// before they are substituted into a replacement list.  Macros in `active`
+/// at the invocation stay blocked while the arguments are expanded.
+fn expand_token_list(
+    macros: &Macros,
+    tokens: Vec<SpannedToken>,
+    active: &ReplacedMacros,
+) -> Vec<SpannedToken> {
+    let mut source = TokenListSource::new(tokens);
+    let mut pushback: VecDeque<ExpandingToken> = VecDeque::new();
+    let mut active = active.clone();
+    let mut output: Vec<SpannedToken> = Vec::new();
+    while let Datum(token) = expand(macros, &mut pushback, &mut active, &mut source) {
+        output.push(token);
+    }
+    output
+}
+
+/// Render a token stream back to source text using each token's recorded
+/// `Spacing`.  Where two expansion-adjacent tokens carry no separating
+/// whitespace but would otherwise re-lex into a single different token (e.g.
+/// `-` `-` becoming `--`), the minimal separating space is inserted.
+fn to_source(tokens: &[SpannedToken]) -> String {
+    let mut output = String::new();
+    for (index, token) in tokens.iter().enumerate() {
+        let rendered = spelling(&token.node);
+        if index > 0 {
+            let previous = &tokens[index - 1];
+            let mut separation = match &previous.spacing {
+                Spacing::Whitespace(whitespace) => whitespace.clone(),
+                Spacing::Alone => String::from(" "),
+                Spacing::Joint => String::new(),
+            };
+            if separation.is_empty() && would_merge(&spelling(&previous.node), &rendered) {
+                separation.push(' ');
+            }
+            output.push_str(&separation);
+        }
+        output.push_str(&rendered);
+    }
+    output
+}
+
+/// Whether placing `right` immediately after `left` would re-lex into a single
+/// token, meaning a separating space is required to keep them distinct.
+fn would_merge(left: &str, right: &str) -> bool {
+    relex(&format!("{}{}", left, right)).is_some()
+}
+
 #[cfg(test)]
 mod tests {
-    use super::PreprocessingToken::*;
+    use super::PreprocessingToken::{Identifier, PreprocessingNumber, StringLiteral};
     use super::*;
     use std::collections::VecDeque;
 
-    type TokenQueue = VecDeque<PreprocessingToken>;
+    type TokenQueue = VecDeque<SpannedToken>;
 
     struct TestTokenSource {
         token_queue: TokenQueue,
     }
 
     impl TestTokenSource {
+        /// Wrap each token with a synthetic single-byte source span so the
+        /// stream carries spans the way a real lexer would.
         fn new(tokens: &Tokens) -> TestTokenSource {
             let mut token_queue = TokenQueue::new();
-            for token in tokens {
-                token_queue.push_back(token.clone());
+            for (index, token) in tokens.iter().enumerate() {
+                token_queue.push_back(Spanned::new(token.clone(), test_span(index)));
             }
             TestTokenSource { token_queue }
         }
     }
 
-    impl Source<PreprocessingToken, String> for TestTokenSource {
+    impl Source<SpannedToken, Diagnostic> for TestTokenSource {
         fn next(&mut self) -> PreprocessingAtom {
             match self.token_queue.pop_front() {
                 Some(token) => Datum(token),
-                None => Empty
+                None => Empty,
             }
         }
     }
 
+    fn test_span(index: usize) -> Span {
+        Span::Source {
+            start: index,
+            end: index + 1,
+            file: FileId(0),
+        }
+    }
+
+    fn expand_all(macros: Macros, tokens: &Tokens) -> Tokens {
+        let mut test_stream = TestTokenSource::new(tokens);
+        let mut expanding_stream = MacroExpandingTokenSource::new(macros, &mut test_stream);
+        let mut result = Vec::new();
+        while let Datum(token) = expanding_stream.next() {
+            result.push(token.node);
+        }
+        result
+    }
+
+    fn spanned(token: PreprocessingToken, index: usize) -> SpannedToken {
+        Spanned::new(token, test_span(index))
+    }
+
+    fn ident(name: &str) -> PreprocessingToken {
+        Identifier(String::from(name))
+    }
+
     #[test]
     fn pass_through() {
         let test_tokens = vec![
@@ -229,8 +1086,379 @@ mod tests {
         let mut expanding_stream = MacroExpandingTokenSource::new(macros, &mut test_stream);
         let mut expansion_result = Vec::new();
         while let Datum(token) = expanding_stream.next() {
-            expansion_result.push(token);
+            expansion_result.push(token.node);
         }
         assert!(expansion_result == test_tokens);
     }
+
+    #[test]
+    fn expands_object_macro() {
+        let mut macros = Macros::new();
+        macros.definitions.insert(
+            String::from("A"),
+            Macro::Object(vec![PreprocessingNumber(String::from("42"))]),
+        );
+        let result = expand_all(macros, &vec![ident("A"), ident("B")]);
+        assert!(result == vec![PreprocessingNumber(String::from("42")), ident("B")]);
+    }
+
+    #[test]
+    fn rescans_object_macro() {
+        let mut macros = Macros::new();
+        macros
+            .definitions
+            .insert(String::from("A"), Macro::Object(vec![ident("B")]));
+        macros.definitions.insert(
+            String::from("B"),
+            Macro::Object(vec![PreprocessingNumber(String::from("1"))]),
+        );
+        let result = expand_all(macros, &vec![ident("A")]);
+        assert!(result == vec![PreprocessingNumber(String::from("1"))]);
+    }
+
+    #[test]
+    fn expands_function_macro() {
+        // #define id(x) x
+        let mut macros = Macros::new();
+        macros.definitions.insert(
+            String::from("id"),
+            Macro::Function(vec![String::from("x")], vec![ident("x")]),
+        );
+        let result = expand_all(
+            macros,
+            &vec![
+                ident("id"),
+                PreprocessingToken::Punctuator(Punctuator::LeftParen),
+                PreprocessingNumber(String::from("7")),
+                PreprocessingToken::Punctuator(Punctuator::RightParen),
+            ],
+        );
+        assert!(result == vec![PreprocessingNumber(String::from("7"))]);
+    }
+
+    #[test]
+    fn nested_parens_are_one_argument() {
+        // #define first(a, b) a
+        let mut macros = Macros::new();
+        macros.definitions.insert(
+            String::from("first"),
+            Macro::Function(
+                vec![String::from("a"), String::from("b")],
+                vec![ident("a")],
+            ),
+        );
+        // first((x, y), z) -> (x, y)
+        let result = expand_all(
+            macros,
+            &vec![
+                ident("first"),
+                PreprocessingToken::Punctuator(Punctuator::LeftParen),
+                PreprocessingToken::Punctuator(Punctuator::LeftParen),
+                ident("x"),
+                PreprocessingToken::Punctuator(Punctuator::ParameterSeparator),
+                ident("y"),
+                PreprocessingToken::Punctuator(Punctuator::RightParen),
+                PreprocessingToken::Punctuator(Punctuator::ParameterSeparator),
+                ident("z"),
+                PreprocessingToken::Punctuator(Punctuator::RightParen),
+            ],
+        );
+        assert!(
+            result
+                == vec![
+                    PreprocessingToken::Punctuator(Punctuator::LeftParen),
+                    ident("x"),
+                    PreprocessingToken::Punctuator(Punctuator::ParameterSeparator),
+                    ident("y"),
+                    PreprocessingToken::Punctuator(Punctuator::RightParen),
+                ]
+        );
+    }
+
+    #[test]
+    fn direct_self_reference_is_not_re_expanded() {
+        // #define f f
+        let mut macros = Macros::new();
+        macros
+            .definitions
+            .insert(String::from("f"), Macro::Object(vec![ident("f")]));
+        let result = expand_all(macros, &vec![ident("f")]);
+        assert!(result == vec![ident("f")]);
+    }
+
+    #[test]
+    fn indirect_recursion_terminates() {
+        // #define a b / #define b a
+        let mut macros = Macros::new();
+        macros
+            .definitions
+            .insert(String::from("a"), Macro::Object(vec![ident("b")]));
+        macros
+            .definitions
+            .insert(String::from("b"), Macro::Object(vec![ident("a")]));
+        let result = expand_all(macros, &vec![ident("a")]);
+        assert!(result == vec![ident("a")]);
+    }
+
+    #[test]
+    fn macro_re_expands_after_scope_closes() {
+        // #define g 1 ; input `g g` expands both occurrences
+        let mut macros = Macros::new();
+        macros.definitions.insert(
+            String::from("g"),
+            Macro::Object(vec![PreprocessingNumber(String::from("1"))]),
+        );
+        let result = expand_all(macros, &vec![ident("g"), ident("g")]);
+        assert!(
+            result
+                == vec![
+                    PreprocessingNumber(String::from("1")),
+                    PreprocessingNumber(String::from("1")),
+                ]
+        );
+    }
+
+    #[test]
+    fn stringize_uses_raw_argument_tokens() {
+        // #define str(x) # x ; str(a b) -> "a b"
+        let mut macros = Macros::new();
+        macros.definitions.insert(
+            String::from("str"),
+            Macro::Function(
+                vec![String::from("x")],
+                vec![
+                    PreprocessingToken::Punctuator(Punctuator::PreprocessingDirective),
+                    ident("x"),
+                ],
+            ),
+        );
+        let result = expand_all(
+            macros,
+            &vec![
+                ident("str"),
+                PreprocessingToken::Punctuator(Punctuator::LeftParen),
+                ident("a"),
+                ident("b"),
+                PreprocessingToken::Punctuator(Punctuator::RightParen),
+            ],
+        );
+        assert!(result == vec![StringLiteral(String::from("a b"))]);
+    }
+
+    #[test]
+    fn concat_pastes_identifiers() {
+        // #define cat(a, b) a ## b ; cat(x, y) -> xy
+        let mut macros = Macros::new();
+        macros.definitions.insert(
+            String::from("cat"),
+            Macro::Function(
+                vec![String::from("a"), String::from("b")],
+                vec![
+                    ident("a"),
+                    PreprocessingToken::Punctuator(Punctuator::PreprocessingConcat),
+                    ident("b"),
+                ],
+            ),
+        );
+        let result = expand_all(
+            macros,
+            &vec![
+                ident("cat"),
+                PreprocessingToken::Punctuator(Punctuator::LeftParen),
+                ident("x"),
+                PreprocessingToken::Punctuator(Punctuator::ParameterSeparator),
+                ident("y"),
+                PreprocessingToken::Punctuator(Punctuator::RightParen),
+            ],
+        );
+        assert!(result == vec![ident("xy")]);
+    }
+
+    #[test]
+    fn concat_pastes_numbers() {
+        let params = vec![String::from("a"), String::from("b")];
+        let replacement = vec![
+            ident("a"),
+            PreprocessingToken::Punctuator(Punctuator::PreprocessingConcat),
+            ident("b"),
+        ];
+        let raw = vec![
+            vec![spanned(PreprocessingNumber(String::from("1")), 0)],
+            vec![spanned(PreprocessingNumber(String::from("2")), 1)],
+        ];
+        let result = substitute(&params, &replacement, &raw, &raw, &test_span(0)).unwrap();
+        let nodes: Tokens = result.into_iter().map(|token| token.node).collect();
+        assert!(nodes == vec![PreprocessingNumber(String::from("12"))]);
+    }
+
+    #[test]
+    fn bad_paste_is_an_error() {
+        let params = vec![String::from("a"), String::from("b")];
+        let replacement = vec![
+            ident("a"),
+            PreprocessingToken::Punctuator(Punctuator::PreprocessingConcat),
+            ident("b"),
+        ];
+        let raw = vec![
+            vec![spanned(PreprocessingToken::Punctuator(Punctuator::Add), 0)],
+            vec![spanned(PreprocessingToken::Punctuator(Punctuator::StatementEnd), 1)],
+        ];
+        let error = substitute(&params, &replacement, &raw, &raw, &test_span(0)).unwrap_err();
+        assert!(error.span.is_some());
+    }
+
+    #[test]
+    fn expanded_token_records_call_site() {
+        // #define A 1 ; the produced `1` carries an Expansion span whose
+        // call_site is the span of the `A` that triggered it.
+        let mut macros = Macros::new();
+        macros.definitions.insert(
+            String::from("A"),
+            Macro::Object(vec![PreprocessingNumber(String::from("1"))]),
+        );
+        let mut test_stream = TestTokenSource::new(&vec![ident("A")]);
+        let mut expanding_stream = MacroExpandingTokenSource::new(macros, &mut test_stream);
+        let token = match expanding_stream.next() {
+            Datum(token) => token,
+            _ => panic!("expected a token"),
+        };
+        match token.span {
+            Span::Expansion { call_site, .. } => assert!(*call_site == test_span(0)),
+            _ => panic!("expected an expansion span"),
+        }
+    }
+
+    #[test]
+    fn to_source_preserves_recorded_spacing() {
+        let tokens = vec![
+            Spanned::spaced(ident("a"), test_span(0), Spacing::Whitespace(String::from("  "))),
+            Spanned::spaced(ident("b"), test_span(1), Spacing::Alone),
+        ];
+        assert!(to_source(&tokens) == "a  b");
+    }
+
+    #[test]
+    fn to_source_inserts_space_to_prevent_merge() {
+        // Two joint `-` tokens must render as `- -`, not `--`.
+        let tokens = vec![
+            Spanned::spaced(PreprocessingToken::Punctuator(Punctuator::Substract), test_span(0), Spacing::Joint),
+            Spanned::spaced(PreprocessingToken::Punctuator(Punctuator::Substract), test_span(1), Spacing::Joint),
+        ];
+        assert!(to_source(&tokens) == "- -");
+    }
+
+    #[test]
+    fn to_source_keeps_joint_when_no_merge() {
+        let tokens = vec![
+            Spanned::spaced(ident("a"), test_span(0), Spacing::Joint),
+            Spanned::spaced(PreprocessingToken::Punctuator(Punctuator::Add), test_span(1), Spacing::Joint),
+        ];
+        assert!(to_source(&tokens) == "a+");
+    }
+
+    fn collect(tokens: &Tokens, parameters: usize) -> Result<Vec<Vec<SpannedToken>>, Diagnostic> {
+        let mut source = TestTokenSource::new(tokens);
+        let mut pushback = VecDeque::new();
+        let mut active = ReplacedMacros::new();
+        collect_arguments(parameters, &mut pushback, &mut active, &mut source)
+    }
+
+    #[test]
+    fn nested_commas_yield_two_arguments() {
+        // The tokens following the invocation's `(` for `f((a, b), c)`.
+        let tokens = vec![
+            PreprocessingToken::Punctuator(Punctuator::LeftParen),
+            ident("a"),
+            PreprocessingToken::Punctuator(Punctuator::ParameterSeparator),
+            ident("b"),
+            PreprocessingToken::Punctuator(Punctuator::RightParen),
+            PreprocessingToken::Punctuator(Punctuator::ParameterSeparator),
+            ident("c"),
+            PreprocessingToken::Punctuator(Punctuator::RightParen),
+        ];
+        let arguments = collect(&tokens, 2).unwrap();
+        assert!(arguments.len() == 2);
+        let second: Tokens = arguments[1].iter().map(|token| token.node.clone()).collect();
+        assert!(second == vec![ident("c")]);
+    }
+
+    #[test]
+    fn missing_right_paren_is_unterminated() {
+        let tokens = vec![ident("a")];
+        let error = collect(&tokens, 1).unwrap_err();
+        assert!(error.message.contains("missing ')'"));
+    }
+
+    #[test]
+    fn mismatched_closer_is_reported() {
+        // `a ]` closes with the wrong delimiter kind.
+        let tokens = vec![ident("a"), PreprocessingToken::Punctuator(Punctuator::ArrayIndexEnd)];
+        let error = collect(&tokens, 1).unwrap_err();
+        assert!(error.span.is_some());
+        assert!(error.message.contains("unexpected closing ']'"));
+    }
+
+    #[test]
+    fn function_macro_without_parens_is_verbatim() {
+        let mut macros = Macros::new();
+        macros.definitions.insert(
+            String::from("id"),
+            Macro::Function(vec![String::from("x")], vec![ident("x")]),
+        );
+        let result = expand_all(macros, &vec![ident("id"), ident("y")]);
+        assert!(result == vec![ident("id"), ident("y")]);
+    }
+
+    #[test]
+    fn too_few_arguments_is_an_error() {
+        // #define f(a, b) a b ; f(x) supplies one argument for two parameters.
+        let mut macros = Macros::new();
+        macros.definitions.insert(
+            String::from("f"),
+            Macro::Function(
+                vec![String::from("a"), String::from("b")],
+                vec![ident("a"), ident("b")],
+            ),
+        );
+        let mut test_stream = TestTokenSource::new(&vec![
+            ident("f"),
+            PreprocessingToken::Punctuator(Punctuator::LeftParen),
+            ident("x"),
+            PreprocessingToken::Punctuator(Punctuator::RightParen),
+        ]);
+        let mut expanding_stream = MacroExpandingTokenSource::new(macros, &mut test_stream);
+        let error = match expanding_stream.next() {
+            Error(error) => error,
+            _ => panic!("expected an error"),
+        };
+        assert!(error.message.contains("passed 1 arguments but takes 2"));
+        assert!(error.span.is_some());
+    }
+
+    #[test]
+    fn too_many_arguments_is_an_error() {
+        // #define id(x) x ; id(x, y, z) supplies three arguments for one parameter.
+        let mut macros = Macros::new();
+        macros.definitions.insert(
+            String::from("id"),
+            Macro::Function(vec![String::from("x")], vec![ident("x")]),
+        );
+        let mut test_stream = TestTokenSource::new(&vec![
+            ident("id"),
+            PreprocessingToken::Punctuator(Punctuator::LeftParen),
+            ident("x"),
+            PreprocessingToken::Punctuator(Punctuator::ParameterSeparator),
+            ident("y"),
+            PreprocessingToken::Punctuator(Punctuator::ParameterSeparator),
+            ident("z"),
+            PreprocessingToken::Punctuator(Punctuator::RightParen),
+        ]);
+        let mut expanding_stream = MacroExpandingTokenSource::new(macros, &mut test_stream);
+        let error = match expanding_stream.next() {
+            Error(error) => error,
+            _ => panic!("expected an error"),
+        };
+        assert!(error.message.contains("passed 3 arguments but takes 1"));
+        assert!(error.span.is_some());
+    }
 }